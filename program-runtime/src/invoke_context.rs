@@ -692,21 +692,14 @@ macro_rules! with_mock_invoke_context {
         );
         let mut sysvar_cache = SysvarCache::default();
         sysvar_cache.fill_missing_entries(|pubkey, callback| {
-            for index in 0..$transaction_context.get_number_of_accounts() {
-                if $transaction_context
-                    .get_key_of_account_at_index(index)
-                    .unwrap()
-                    == pubkey
-                {
-                    callback(
-                        $transaction_context
-                            .get_account_at_index(index)
-                            .unwrap()
-                            .borrow()
-                            .data(),
-                    );
-                }
-            }
+            $transaction_context
+                .scan_accounts(|key, account| {
+                    if key == pubkey {
+                        callback(account.data());
+                    }
+                    None::<()>
+                })
+                .unwrap();
         });
         let environment_config = EnvironmentConfig::new(
             Hash::default(),
@@ -0,0 +1,59 @@
+#![feature(test)]
+
+extern crate test;
+use {
+    solana_sdk::{
+        account::AccountSharedData,
+        pubkey::Pubkey,
+        rent::Rent,
+        transaction_context::{IndexOfAccount, TransactionAccount, TransactionContext},
+    },
+    test::Bencher,
+};
+
+const NUM_ACCOUNTS: usize = 64;
+const ACCOUNT_DATA_LEN: usize = 10 * 1024;
+
+fn create_transaction_accounts() -> Vec<TransactionAccount> {
+    (0..NUM_ACCOUNTS)
+        .map(|_| {
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(1, ACCOUNT_DATA_LEN, &Pubkey::new_unique()),
+            )
+        })
+        .collect()
+}
+
+#[bench]
+fn bench_transaction_accounts_try_borrow(b: &mut Bencher) {
+    let transaction_context =
+        TransactionContext::new(create_transaction_accounts(), Rent::default(), 1, 1);
+    let accounts = transaction_context.accounts();
+    b.iter(|| {
+        for index in 0..NUM_ACCOUNTS as IndexOfAccount {
+            test::black_box(accounts.try_borrow(index).unwrap());
+        }
+    });
+}
+
+#[bench]
+fn bench_transaction_accounts_try_borrow_mut(b: &mut Bencher) {
+    let transaction_context =
+        TransactionContext::new(create_transaction_accounts(), Rent::default(), 1, 1);
+    let accounts = transaction_context.accounts();
+    b.iter(|| {
+        for index in 0..NUM_ACCOUNTS as IndexOfAccount {
+            test::black_box(accounts.try_borrow_mut(index).unwrap());
+        }
+    });
+}
+
+#[bench]
+fn bench_transaction_context_new(b: &mut Bencher) {
+    let transaction_accounts = create_transaction_accounts();
+    b.iter(|| {
+        // Cloning only bumps the reference counts of the account data
+        TransactionContext::new(transaction_accounts.clone(), Rent::default(), 1, 1)
+    });
+}
@@ -1,4 +1,7 @@
 //! Data shared between program runtime and built-in programs as well as SBF programs.
+//!
+//! Any map whose iteration order can influence output (including `Debug`) must be
+//! a `BTreeMap` or be sorted before use, so that all validators observe the same order.
 #![deny(clippy::indexing_slicing)]
 
 #[cfg(all(not(target_os = "solana"), feature = "full", debug_assertions))]
@@ -7,13 +10,16 @@ use crate::signature::Signature;
 use {
     crate::{
         account::WritableAccount,
+        hash::{Hash, Hasher},
         rent::Rent,
         system_instruction::{
             MAX_PERMITTED_ACCOUNTS_DATA_ALLOCATIONS_PER_TRANSACTION, MAX_PERMITTED_DATA_LENGTH,
         },
+        sysvar::{self, Sysvar},
+        transaction::TransactionError,
     },
     solana_program::entrypoint::MAX_PERMITTED_DATA_INCREASE,
-    std::mem::MaybeUninit,
+    std::{any::Any, collections::BTreeMap, fmt, mem::MaybeUninit},
 };
 use {
     crate::{
@@ -21,9 +27,12 @@ use {
         instruction::InstructionError,
         pubkey::Pubkey,
     },
+    bytemuck::Pod,
     std::{
         cell::{Ref, RefCell, RefMut},
         collections::HashSet,
+        mem::size_of,
+        ops::Range,
         pin::Pin,
         rc::Rc,
     },
@@ -56,10 +65,46 @@ pub struct InstructionAccount {
 /// An account key and the matching account
 pub type TransactionAccount = (Pubkey, AccountSharedData);
 
+/// Sysvars deserialized by `TransactionContext::read_sysvar()`, keyed by account index
+///
+/// Each entry keeps the account data it was deserialized from and is only
+/// served while the account still holds exactly that data, no matter how the
+/// account was mutated in between.
+/// The cache is transparent: Cloning yields an empty cache and it never takes
+/// part in equality comparisons.
+#[cfg(not(target_os = "solana"))]
+#[derive(Default)]
+struct SysvarReadCache(RefCell<BTreeMap<IndexOfAccount, (Vec<u8>, Rc<dyn Any>)>>);
+
+#[cfg(not(target_os = "solana"))]
+impl Clone for SysvarReadCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(not(target_os = "solana"))]
+impl fmt::Debug for SysvarReadCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SysvarReadCache")
+            .field("cached_indices", &self.0.borrow().keys())
+            .finish()
+    }
+}
+
+#[cfg(not(target_os = "solana"))]
+impl PartialEq for SysvarReadCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct TransactionAccounts {
     accounts: Vec<RefCell<AccountSharedData>>,
     touched_flags: RefCell<Box<[bool]>>,
+    #[cfg(not(target_os = "solana"))]
+    sysvar_read_cache: SysvarReadCache,
 }
 
 impl TransactionAccounts {
@@ -68,6 +113,7 @@ impl TransactionAccounts {
         TransactionAccounts {
             touched_flags: RefCell::new(vec![false; accounts.len()].into_boxed_slice()),
             accounts,
+            sysvar_read_cache: SysvarReadCache::default(),
         }
     }
 
@@ -121,6 +167,103 @@ impl TransactionAccounts {
             .map_err(|_| InstructionError::AccountBorrowFailed)
     }
 
+    /// Deserializes the sysvar stored in the account at the given index
+    ///
+    /// The caller must have checked that the key of the account is the id of `T`.
+    #[cfg(not(target_os = "solana"))]
+    fn read_sysvar<T: Sysvar + Clone + 'static>(
+        &self,
+        index: IndexOfAccount,
+    ) -> Result<T, InstructionError> {
+        let account = self.try_borrow(index)?;
+        if !sysvar::check_id(account.owner()) {
+            return Err(InstructionError::InvalidArgument);
+        }
+        if let Some(sysvar) = self
+            .sysvar_read_cache
+            .0
+            .borrow()
+            .get(&index)
+            .filter(|(data, _)| data.as_slice() == account.data())
+            .and_then(|(_, cached)| cached.downcast_ref::<T>())
+        {
+            return Ok(sysvar.clone());
+        }
+        let sysvar = account
+            .deserialize_data::<T>()
+            .map_err(|_| InstructionError::InvalidArgument)?;
+        self.sysvar_read_cache
+            .0
+            .borrow_mut()
+            .insert(index, (account.data().to_vec(), Rc::new(sysvar.clone())));
+        Ok(sysvar)
+    }
+
+    /// Returns whether the data of the account at the given index equals `expected`
+    #[cfg(not(target_os = "solana"))]
+    pub fn data_matches(
+        &self,
+        index: IndexOfAccount,
+        expected: &[u8],
+    ) -> Result<bool, InstructionError> {
+        Ok(self.try_borrow(index)?.data() == expected)
+    }
+
+    /// Deserializes the data of the account at the given index into a state
+    #[cfg(not(target_os = "solana"))]
+    pub fn deserialize_data<T: serde::de::DeserializeOwned>(
+        &self,
+        index: IndexOfAccount,
+    ) -> Result<T, InstructionError> {
+        self.try_borrow(index)?
+            .deserialize_data()
+            .map_err(|_| InstructionError::InvalidAccountData)
+    }
+
+    /// Returns the indices of all accounts currently owned by the given program
+    #[cfg(not(target_os = "solana"))]
+    pub fn indices_owned_by(
+        &self,
+        owner: &Pubkey,
+    ) -> Result<Vec<IndexOfAccount>, InstructionError> {
+        self.iter_indices_owned_by(owner).collect()
+    }
+
+    /// Iterates the indices of all accounts currently owned by the given program
+    ///
+    /// Yields `AccountBorrowFailed` for accounts which are borrowed mutably.
+    #[cfg(not(target_os = "solana"))]
+    pub fn iter_indices_owned_by<'a>(
+        &'a self,
+        owner: &'a Pubkey,
+    ) -> impl Iterator<Item = Result<IndexOfAccount, InstructionError>> + 'a {
+        self.accounts
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, account)| match account.try_borrow() {
+                Ok(account) => (account.owner() == owner).then_some(Ok(index as IndexOfAccount)),
+                Err(_) => Some(Err(InstructionError::AccountBorrowFailed)),
+            })
+    }
+
+    /// Returns the sum of the current data lengths of all accounts
+    ///
+    /// This is not the quantity the loaded accounts data size limit is checked
+    /// against: At load time the instructions sysvar is skipped and program
+    /// accounts loaded as stubs are counted with their full size.
+    /// See `TransactionContext::enforce_loaded_data_size_limit()` instead.
+    #[cfg(not(target_os = "solana"))]
+    pub fn total_data_size(&self) -> Result<u64, InstructionError> {
+        self.accounts
+            .iter()
+            .try_fold(0u64, |total_data_size, account| {
+                let account = account
+                    .try_borrow()
+                    .map_err(|_| InstructionError::AccountBorrowFailed)?;
+                Ok(total_data_size.saturating_add(account.data().len() as u64))
+            })
+    }
+
     pub fn into_accounts(self) -> Vec<AccountSharedData> {
         self.accounts
             .into_iter()
@@ -189,6 +332,45 @@ impl TransactionContext {
             .into_accounts())
     }
 
+    /// Partitions the touched accounts into the ones to be written back and
+    /// the keys of the ones to be purged.
+    ///
+    /// An account is purged if it was drained to zero lamports and has no data
+    /// left. Accounts which were never touched are left out of both sets.
+    ///
+    /// Fails with `CallDepth` if an instruction is still on the stack.
+    /// Panics if the `TransactionAccounts` have outstanding references.
+    #[cfg(not(target_os = "solana"))]
+    pub fn drain_into_commit_set(
+        self,
+    ) -> Result<(Vec<TransactionAccount>, Vec<Pubkey>), InstructionError> {
+        if !self.instruction_stack.is_empty() {
+            return Err(InstructionError::CallDepth);
+        }
+
+        let accounts = Rc::try_unwrap(self.accounts)
+            .expect("transaction_context.accounts has unexpected outstanding refs");
+        let touched_flags = accounts.touched_flags.into_inner();
+        let mut write_set = Vec::with_capacity(touched_flags.len());
+        let mut purge_set = Vec::new();
+        for ((key, account), was_touched) in Vec::from(Pin::into_inner(self.account_keys))
+            .into_iter()
+            .zip(accounts.accounts)
+            .zip(touched_flags.into_vec())
+        {
+            if !was_touched {
+                continue;
+            }
+            let account = account.into_inner();
+            if account.lamports() == 0 && account.data().is_empty() {
+                purge_set.push(key);
+            } else {
+                write_set.push((key, account));
+            }
+        }
+        Ok((write_set, purge_set))
+    }
+
     #[cfg(not(target_os = "solana"))]
     pub fn accounts(&self) -> &Rc<TransactionAccounts> {
         &self.accounts
@@ -211,6 +393,16 @@ impl TransactionContext {
         self.accounts.len() as IndexOfAccount
     }
 
+    /// Returns the keys of all accounts loaded in this Transaction
+    pub fn get_account_keys(&self) -> &[Pubkey] {
+        &self.account_keys
+    }
+
+    /// Returns the key of the fee payer, which is always the first account
+    pub fn get_fee_payer_key(&self) -> Result<&Pubkey, InstructionError> {
+        self.get_key_of_account_at_index(0)
+    }
+
     /// Searches for an account by its key
     pub fn get_key_of_account_at_index(
         &self,
@@ -232,6 +424,68 @@ impl TransactionContext {
             .ok_or(InstructionError::NotEnoughAccountKeys)
     }
 
+    /// Deserializes the sysvar `T` stored in the account at the given index
+    ///
+    /// Fails with `InvalidArgument` if the key of the account is not the id of `T`
+    /// or the account is not owned by the sysvar program, and with
+    /// `AccountBorrowFailed` if the account is borrowed mutably.
+    /// The result is cached for as long as the account data stays the same.
+    #[cfg(not(target_os = "solana"))]
+    pub fn read_sysvar<T: Sysvar + Clone + 'static>(
+        &self,
+        index_in_transaction: IndexOfAccount,
+    ) -> Result<T, InstructionError> {
+        if !T::check_id(self.get_key_of_account_at_index(index_in_transaction)?) {
+            return Err(InstructionError::InvalidArgument);
+        }
+        self.accounts.read_sysvar(index_in_transaction)
+    }
+
+    /// Collects the non-`None` results of `f` over all accounts and their keys
+    ///
+    /// Fails with `AccountBorrowFailed` if any account is borrowed mutably.
+    #[cfg(not(target_os = "solana"))]
+    pub fn scan_accounts<R>(
+        &self,
+        mut f: impl FnMut(&Pubkey, &AccountSharedData) -> Option<R>,
+    ) -> Result<Vec<R>, InstructionError> {
+        let mut results = Vec::new();
+        for (index_in_transaction, key) in self.account_keys.iter().enumerate() {
+            let account = self
+                .accounts
+                .try_borrow(index_in_transaction as IndexOfAccount)?;
+            if let Some(result) = f(key, &account) {
+                results.push(result);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Hashes the state of all accounts in index order
+    ///
+    /// Includes the key, lamports, owner, executable flag, data length and data
+    /// of every account, whether it was touched or not.
+    /// Fails with `AccountBorrowFailed` if any account is borrowed mutably.
+    #[cfg(not(target_os = "solana"))]
+    pub fn state_digest(&self) -> Result<Hash, InstructionError> {
+        let mut hasher = Hasher::default();
+        hasher.hash(b"TransactionContext::state_digest");
+        for (index_in_transaction, key) in self.account_keys.iter().enumerate() {
+            let account = self
+                .accounts
+                .try_borrow(index_in_transaction as IndexOfAccount)?;
+            hasher.hashv(&[
+                key.as_ref(),
+                &account.lamports().to_le_bytes(),
+                account.owner().as_ref(),
+                &[account.executable() as u8],
+                &(account.data().len() as u64).to_le_bytes(),
+                account.data(),
+            ]);
+        }
+        Ok(hasher.result())
+    }
+
     /// Searches for an account by its key
     pub fn find_index_of_account(&self, pubkey: &Pubkey) -> Option<IndexOfAccount> {
         self.account_keys
@@ -437,6 +691,163 @@ impl TransactionContext {
             .map_err(|_| InstructionError::GenericError)
             .map(|value_ref| *value_ref)
     }
+
+    /// Re-checks the loaded accounts data size limit after accounts grew during execution
+    ///
+    /// `loaded_accounts_data_size` is the size accumulated by the account
+    /// loader, to which the accounts resize delta is added.
+    #[cfg(not(target_os = "solana"))]
+    pub fn enforce_loaded_data_size_limit(
+        &self,
+        loaded_accounts_data_size: usize,
+        limit: u32,
+    ) -> Result<(), TransactionError> {
+        let accounts_resize_delta = self
+            .accounts_resize_delta()
+            .map_err(|_| TransactionError::AccountBorrowOutstanding)?;
+        let total_data_size =
+            (loaded_accounts_data_size as i64).saturating_add(accounts_resize_delta);
+        if total_data_size > limit as i64 {
+            return Err(TransactionError::MaxLoadedAccountsDataSizeExceeded);
+        }
+        Ok(())
+    }
+}
+
+/// A field of an account which differs between two `TransactionContext`s
+#[cfg(all(
+    not(target_os = "solana"),
+    any(test, feature = "dev-context-only-utils")
+))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AccountFieldDiff {
+    Key {
+        before: Pubkey,
+        after: Pubkey,
+    },
+    Lamports {
+        before: u64,
+        after: u64,
+    },
+    Owner {
+        before: Pubkey,
+        after: Pubkey,
+    },
+    Executable {
+        before: bool,
+        after: bool,
+    },
+    RentEpoch {
+        before: u64,
+        after: u64,
+    },
+    /// Data diffs are summarized by the first differing offset and the lengths
+    Data {
+        first_differing_offset: usize,
+        before_len: usize,
+        after_len: usize,
+    },
+}
+
+/// All fields of an account which differ between two `TransactionContext`s
+#[cfg(all(
+    not(target_os = "solana"),
+    any(test, feature = "dev-context-only-utils")
+))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccountDiff {
+    pub index_in_transaction: IndexOfAccount,
+    pub key: Pubkey,
+    pub fields: Vec<AccountFieldDiff>,
+}
+
+#[cfg(all(
+    not(target_os = "solana"),
+    any(test, feature = "dev-context-only-utils")
+))]
+impl TransactionContext {
+    /// Compares the accounts of two `TransactionContext`s index by index
+    ///
+    /// Fails with `NotEnoughAccountKeys` if the number of accounts differs.
+    pub fn diff(&self, other: &TransactionContext) -> Result<Vec<AccountDiff>, InstructionError> {
+        if self.get_number_of_accounts() != other.get_number_of_accounts() {
+            return Err(InstructionError::NotEnoughAccountKeys);
+        }
+        let mut diffs = Vec::new();
+        for index_in_transaction in 0..self.get_number_of_accounts() {
+            let key = *self.get_key_of_account_at_index(index_in_transaction)?;
+            let other_key = *other.get_key_of_account_at_index(index_in_transaction)?;
+            let before = self.accounts.try_borrow(index_in_transaction)?;
+            let after = other.accounts.try_borrow(index_in_transaction)?;
+            let mut fields = Vec::new();
+            if key != other_key {
+                fields.push(AccountFieldDiff::Key {
+                    before: key,
+                    after: other_key,
+                });
+            }
+            if before.lamports() != after.lamports() {
+                fields.push(AccountFieldDiff::Lamports {
+                    before: before.lamports(),
+                    after: after.lamports(),
+                });
+            }
+            if before.owner() != after.owner() {
+                fields.push(AccountFieldDiff::Owner {
+                    before: *before.owner(),
+                    after: *after.owner(),
+                });
+            }
+            if before.executable() != after.executable() {
+                fields.push(AccountFieldDiff::Executable {
+                    before: before.executable(),
+                    after: after.executable(),
+                });
+            }
+            if before.rent_epoch() != after.rent_epoch() {
+                fields.push(AccountFieldDiff::RentEpoch {
+                    before: before.rent_epoch(),
+                    after: after.rent_epoch(),
+                });
+            }
+            if before.data() != after.data() {
+                let first_differing_offset = before
+                    .data()
+                    .iter()
+                    .zip(after.data())
+                    .position(|(before, after)| before != after)
+                    .unwrap_or_else(|| before.data().len().min(after.data().len()));
+                fields.push(AccountFieldDiff::Data {
+                    first_differing_offset,
+                    before_len: before.data().len(),
+                    after_len: after.data().len(),
+                });
+            }
+            if !fields.is_empty() {
+                diffs.push(AccountDiff {
+                    index_in_transaction,
+                    key,
+                    fields,
+                });
+            }
+        }
+        Ok(diffs)
+    }
+}
+
+/// Panics with a readable summary if the accounts of two `TransactionContext`s differ
+#[cfg(all(
+    not(target_os = "solana"),
+    any(test, feature = "dev-context-only-utils")
+))]
+#[track_caller]
+pub fn assert_same_accounts(left: &TransactionContext, right: &TransactionContext) {
+    let diffs = left
+        .diff(right)
+        .unwrap_or_else(|err| panic!("accounts can not be compared: {err}"));
+    if !diffs.is_empty() {
+        panic!("accounts differ: {diffs:#?}");
+    }
 }
 
 /// Return data at the end of a transaction
@@ -602,10 +1013,7 @@ impl InstructionContext {
     ) -> Result<BorrowedAccount<'a>, InstructionError> {
         let account = transaction_context
             .accounts
-            .get(index_in_transaction)
-            .ok_or(InstructionError::MissingAccount)?
-            .try_borrow_mut()
-            .map_err(|_| InstructionError::AccountBorrowFailed)?;
+            .try_borrow_mut(index_in_transaction)?;
         Ok(BorrowedAccount {
             transaction_context,
             instruction_context: self,
@@ -830,6 +1238,37 @@ impl<'a> BorrowedAccount<'a> {
         Ok(self.account.data_as_mut_slice())
     }
 
+    /// Returns a read-only sub-range of the account data (transaction wide)
+    pub fn get_data_range(&self, range: Range<usize>) -> Result<&[u8], InstructionError> {
+        self.get_data()
+            .get(range)
+            .ok_or(InstructionError::AccountDataTooSmall)
+    }
+
+    /// Returns a writable sub-range of the account data (transaction wide)
+    #[cfg(not(target_os = "solana"))]
+    pub fn get_data_range_mut(
+        &mut self,
+        range: Range<usize>,
+    ) -> Result<&mut [u8], InstructionError> {
+        // Check the bounds first, so that an invalid range does not touch the account
+        self.get_data_range(range.clone())?;
+        self.get_data_mut()?
+            .get_mut(range)
+            .ok_or(InstructionError::AccountDataTooSmall)
+    }
+
+    /// Reads a fixed-layout value at the given offset of the account data (transaction wide)
+    ///
+    /// The offset does not need to be aligned for `T`.
+    pub fn read_pod<T: Pod>(&self, offset: usize) -> Result<T, InstructionError> {
+        let end = offset
+            .checked_add(size_of::<T>())
+            .ok_or(InstructionError::AccountDataTooSmall)?;
+        bytemuck::try_pod_read_unaligned(self.get_data_range(offset..end)?)
+            .map_err(|_| InstructionError::InvalidAccountData)
+    }
+
     /// Returns the spare capacity of the vector backing the account data.
     ///
     /// This method should only ever be used during CPI, where after a shrinking
@@ -1176,3 +1615,634 @@ fn is_zeroed(buf: &[u8]) -> bool {
             && chunks.remainder() == &ZEROS[..chunks.remainder().len()]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            account::{create_account_shared_data_for_test, to_account, Account},
+            bpf_loader,
+            clock::Clock,
+            nonce::{
+                state::{Data, DurableNonce, Versions},
+                State,
+            },
+            system_program,
+            sysvar::{instructions, SysvarId},
+        },
+    };
+
+    fn new_transaction_context(
+        transaction_accounts: Vec<TransactionAccount>,
+    ) -> TransactionContext {
+        TransactionContext::new(transaction_accounts, Rent::default(), 1, 1)
+    }
+
+    #[test]
+    fn test_drain_into_commit_set() {
+        let untouched_key = Pubkey::new_unique();
+        let drained_key = Pubkey::new_unique();
+        let modified_key = Pubkey::new_unique();
+        let drained_with_data_key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let mut transaction_context = new_transaction_context(vec![
+            (
+                untouched_key,
+                AccountSharedData::new(1, 0, &system_program::id()),
+            ),
+            (
+                drained_key,
+                AccountSharedData::new(42, 0, &system_program::id()),
+            ),
+            (
+                modified_key,
+                AccountSharedData::new(100, 3, &system_program::id()),
+            ),
+            (drained_with_data_key, AccountSharedData::new(7, 2, &owner)),
+        ]);
+
+        let accounts = transaction_context.accounts();
+        accounts.try_borrow_mut(1).unwrap().set_lamports(0);
+        accounts.touch(1).unwrap();
+        accounts.try_borrow_mut(3).unwrap().set_lamports(0);
+        accounts.touch(3).unwrap();
+        {
+            let mut account = accounts.try_borrow_mut(2).unwrap();
+            account.set_lamports(50);
+            account.set_owner(owner);
+            account.data_as_mut_slice().copy_from_slice(&[1, 2, 3]);
+        }
+        accounts.touch(2).unwrap();
+
+        let mut expected_account = AccountSharedData::new(50, 3, &owner);
+        expected_account
+            .data_as_mut_slice()
+            .copy_from_slice(&[1, 2, 3]);
+        // Zero lamports alone do not purge an account which still holds data
+        let drained_with_data_account = AccountSharedData::new(0, 2, &owner);
+
+        // Not allowed while an instruction is still on the stack
+        transaction_context.push().unwrap();
+        assert_eq!(
+            transaction_context.clone().drain_into_commit_set(),
+            Err(InstructionError::CallDepth)
+        );
+        transaction_context.pop().unwrap();
+
+        let (write_set, purge_set) = transaction_context.drain_into_commit_set().unwrap();
+        assert_eq!(
+            write_set,
+            vec![
+                (modified_key, expected_account),
+                (drained_with_data_key, drained_with_data_account),
+            ]
+        );
+        assert_eq!(purge_set, vec![drained_key]);
+    }
+
+    #[test]
+    fn test_account_keys_survive_mutable_borrow() {
+        let key = Pubkey::new_unique();
+        let transaction_context = new_transaction_context(vec![(
+            key,
+            AccountSharedData::new(1, 0, &system_program::id()),
+        )]);
+
+        // The keys live outside of the `TransactionAccounts`, so a mutable
+        // borrow only ever exposes the `AccountSharedData` and not the key.
+        let mut account: RefMut<'_, AccountSharedData> =
+            transaction_context.accounts().try_borrow_mut(0).unwrap();
+        account.set_owner(Pubkey::new_unique());
+        account.set_lamports(2);
+        assert_eq!(
+            transaction_context.get_key_of_account_at_index(0).unwrap(),
+            &key
+        );
+        assert_eq!(transaction_context.find_index_of_account(&key), Some(0));
+    }
+
+    #[test]
+    fn test_read_sysvar() {
+        let clock = Clock {
+            slot: 7,
+            unix_timestamp: 1_700_000_000,
+            ..Clock::default()
+        };
+        let rent = Rent {
+            lamports_per_byte_year: 42,
+            ..Rent::default()
+        };
+        let transaction_context = new_transaction_context(vec![
+            (Clock::id(), create_account_shared_data_for_test(&clock)),
+            (Rent::id(), create_account_shared_data_for_test(&rent)),
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(1, 0, &system_program::id()),
+            ),
+        ]);
+
+        // The wrong sysvar type at a valid sysvar index is rejected and not cached
+        assert_eq!(
+            transaction_context.read_sysvar::<Rent>(0),
+            Err(InstructionError::InvalidArgument)
+        );
+        assert_eq!(
+            format!("{:?}", transaction_context.accounts().sysvar_read_cache),
+            "SysvarReadCache { cached_indices: [] }"
+        );
+
+        assert_eq!(
+            transaction_context.read_sysvar::<Clock>(0),
+            Ok(clock.clone())
+        );
+        assert_eq!(transaction_context.read_sysvar::<Rent>(1), Ok(rent));
+        assert_eq!(
+            transaction_context.read_sysvar::<Rent>(0),
+            Err(InstructionError::InvalidArgument)
+        );
+        // Served from the cache, even while a program holds a read borrow
+        {
+            let _account = transaction_context.accounts().try_borrow(0).unwrap();
+            assert_eq!(transaction_context.read_sysvar::<Clock>(0), Ok(clock));
+        }
+        assert_eq!(
+            transaction_context.read_sysvar::<Clock>(2),
+            Err(InstructionError::InvalidArgument)
+        );
+        assert_eq!(
+            transaction_context.read_sysvar::<Clock>(3),
+            Err(InstructionError::NotEnoughAccountKeys)
+        );
+    }
+
+    #[test]
+    fn test_read_sysvar_checks_owner() {
+        let mut account = create_account_shared_data_for_test(&Clock::default());
+        account.set_owner(system_program::id());
+        let transaction_context = new_transaction_context(vec![(Clock::id(), account)]);
+        assert_eq!(
+            transaction_context.read_sysvar::<Clock>(0),
+            Err(InstructionError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_read_sysvar_cache_observes_mutation_through_refcell() {
+        let clock = Clock {
+            slot: 7,
+            ..Clock::default()
+        };
+        let transaction_context = new_transaction_context(vec![(
+            Clock::id(),
+            create_account_shared_data_for_test(&clock),
+        )]);
+        let account = transaction_context.get_account_at_index(0).unwrap();
+        assert_eq!(transaction_context.read_sysvar::<Clock>(0), Ok(clock));
+
+        // Bypasses `try_borrow_mut()` and `touch()` entirely
+        let updated_clock = Clock {
+            slot: 8,
+            ..Clock::default()
+        };
+        to_account(&updated_clock, &mut *account.borrow_mut()).unwrap();
+        assert_eq!(
+            transaction_context.read_sysvar::<Clock>(0),
+            Ok(updated_clock)
+        );
+    }
+
+    #[test]
+    fn test_enforce_loaded_data_size_limit() {
+        const PROGRAM_ACCOUNT_SIZE: usize = 1000;
+        let program_id = Pubkey::new_unique();
+        let mut program_account = AccountSharedData::new(1, 0, &bpf_loader::id());
+        program_account.set_executable(true);
+        let mut transaction_context = new_transaction_context(vec![
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(1, 10, &program_id),
+            ),
+            (
+                instructions::id(),
+                AccountSharedData::from(Account {
+                    data: instructions::construct_instructions_data(&[]),
+                    owner: sysvar::id(),
+                    ..Account::default()
+                }),
+            ),
+            // Program accounts are loaded as stubs without data
+            (program_id, program_account),
+        ]);
+        // The account loader skips the instructions sysvar and counts the full program size
+        let loaded_accounts_data_size = 10 + PROGRAM_ACCOUNT_SIZE;
+        let limit = loaded_accounts_data_size as u32;
+        assert_ne!(
+            transaction_context.accounts().total_data_size(),
+            Ok(loaded_accounts_data_size as u64)
+        );
+        assert_eq!(
+            transaction_context.enforce_loaded_data_size_limit(loaded_accounts_data_size, limit),
+            Ok(())
+        );
+        assert_eq!(
+            transaction_context
+                .enforce_loaded_data_size_limit(loaded_accounts_data_size, limit - 1),
+            Err(TransactionError::MaxLoadedAccountsDataSizeExceeded)
+        );
+
+        // Grow an account past the limit mid-execution
+        transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(
+                &[2],
+                &[InstructionAccount {
+                    index_in_transaction: 0,
+                    index_in_caller: 0,
+                    index_in_callee: 0,
+                    is_signer: false,
+                    is_writable: true,
+                }],
+                &[],
+            );
+        transaction_context.push().unwrap();
+        transaction_context
+            .get_current_instruction_context()
+            .unwrap()
+            .try_borrow_instruction_account(&transaction_context, 0)
+            .unwrap()
+            .set_data_length(11)
+            .unwrap();
+        assert_eq!(
+            transaction_context.enforce_loaded_data_size_limit(loaded_accounts_data_size, limit),
+            Err(TransactionError::MaxLoadedAccountsDataSizeExceeded)
+        );
+        assert_eq!(
+            transaction_context
+                .enforce_loaded_data_size_limit(loaded_accounts_data_size, limit + 1),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_borrowed_account_data_range() {
+        let program_id = Pubkey::new_unique();
+        let mut account = AccountSharedData::new(1, 8, &program_id);
+        account
+            .data_as_mut_slice()
+            .copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut transaction_context = new_transaction_context(vec![
+            (
+                program_id,
+                AccountSharedData::new(1, 0, &Pubkey::new_unique()),
+            ),
+            (Pubkey::new_unique(), account),
+        ]);
+        transaction_context
+            .get_next_instruction_context()
+            .unwrap()
+            .configure(
+                &[0],
+                &[InstructionAccount {
+                    index_in_transaction: 1,
+                    index_in_caller: 1,
+                    index_in_callee: 0,
+                    is_signer: false,
+                    is_writable: true,
+                }],
+                &[],
+            );
+        transaction_context.push().unwrap();
+        let instruction_context = transaction_context
+            .get_current_instruction_context()
+            .unwrap();
+        let mut account = instruction_context
+            .try_borrow_instruction_account(&transaction_context, 0)
+            .unwrap();
+
+        // In bounds
+        assert_eq!(account.get_data_range(2..5), Ok(&[3, 4, 5][..]));
+        assert_eq!(account.get_data_range(8..8), Ok(&[][..]));
+        // Straddling the end
+        assert_eq!(
+            account.get_data_range(6..9),
+            Err(InstructionError::AccountDataTooSmall)
+        );
+        assert_eq!(
+            account.get_data_range_mut(6..9),
+            Err(InstructionError::AccountDataTooSmall)
+        );
+        assert_eq!(transaction_context.accounts().touched_count(), 0);
+
+        account
+            .get_data_range_mut(0..2)
+            .unwrap()
+            .copy_from_slice(&[9, 9]);
+        assert_eq!(account.get_data(), &[9, 9, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(transaction_context.accounts().touched_count(), 1);
+
+        // Unaligned reads
+        assert_eq!(
+            account.read_pod::<u32>(1),
+            Ok(u32::from_ne_bytes([9, 3, 4, 5]))
+        );
+        assert_eq!(
+            account.read_pod::<u16>(7),
+            Err(InstructionError::AccountDataTooSmall)
+        );
+        assert_eq!(
+            account.read_pod::<u64>(usize::MAX),
+            Err(InstructionError::AccountDataTooSmall)
+        );
+    }
+
+    #[test]
+    fn test_indices_owned_by() {
+        let owner_a = Pubkey::new_unique();
+        let owner_b = Pubkey::new_unique();
+        let transaction_context = new_transaction_context(vec![
+            (Pubkey::new_unique(), AccountSharedData::new(1, 0, &owner_a)),
+            (Pubkey::new_unique(), AccountSharedData::new(1, 0, &owner_b)),
+            (Pubkey::new_unique(), AccountSharedData::new(1, 0, &owner_a)),
+        ]);
+        let accounts = transaction_context.accounts();
+        assert_eq!(accounts.indices_owned_by(&owner_a), Ok(vec![0, 2]));
+        assert_eq!(accounts.indices_owned_by(&owner_b), Ok(vec![1]));
+        assert_eq!(accounts.indices_owned_by(&Pubkey::new_unique()), Ok(vec![]));
+
+        // Reflects the current owner, not the initial one
+        accounts.try_borrow_mut(2).unwrap().set_owner(owner_b);
+        assert_eq!(accounts.indices_owned_by(&owner_a), Ok(vec![0]));
+        assert_eq!(accounts.indices_owned_by(&owner_b), Ok(vec![1, 2]));
+
+        let _account = accounts.try_borrow_mut(1).unwrap();
+        assert_eq!(
+            accounts.indices_owned_by(&owner_b),
+            Err(InstructionError::AccountBorrowFailed)
+        );
+        assert_eq!(accounts.iter_indices_owned_by(&owner_a).next(), Some(Ok(0)));
+    }
+
+    #[test]
+    fn test_data_matches_and_deserialize_data() {
+        let durable_nonce = DurableNonce::from_blockhash(&Hash::new_unique());
+        let nonce_versions = Versions::new(State::Initialized(Data::new(
+            Pubkey::new_unique(),
+            durable_nonce,
+            5000,
+        )));
+        let nonce_account =
+            AccountSharedData::new_data(1, &nonce_versions, &system_program::id()).unwrap();
+        let expected_data = bincode::serialize(&nonce_versions).unwrap();
+        let transaction_context = new_transaction_context(vec![
+            (Pubkey::new_unique(), nonce_account),
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(1, 0, &system_program::id()),
+            ),
+        ]);
+        let accounts = transaction_context.accounts();
+
+        assert_eq!(accounts.data_matches(0, &expected_data), Ok(true));
+        let mut other_data = expected_data.clone();
+        *other_data.last_mut().unwrap() ^= 1;
+        assert_eq!(accounts.data_matches(0, &other_data), Ok(false));
+        assert_eq!(
+            accounts.data_matches(0, expected_data.split_last().unwrap().1),
+            Ok(false)
+        );
+        assert_eq!(
+            accounts.data_matches(2, &expected_data),
+            Err(InstructionError::MissingAccount)
+        );
+
+        assert_eq!(accounts.deserialize_data::<Versions>(0), Ok(nonce_versions));
+        assert_eq!(
+            accounts.deserialize_data::<Versions>(1),
+            Err(InstructionError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_scan_accounts() {
+        let token_program_id = Pubkey::new_unique();
+        let token_account_key = Pubkey::new_unique();
+        let transaction_context = new_transaction_context(vec![
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(1, 0, &system_program::id()),
+            ),
+            (
+                token_account_key,
+                AccountSharedData::new(2, 0, &token_program_id),
+            ),
+        ]);
+        let scan_token_balances = || {
+            transaction_context.scan_accounts(|key, account| {
+                (account.owner() == &token_program_id).then_some((*key, account.lamports()))
+            })
+        };
+        assert_eq!(scan_token_balances(), Ok(vec![(token_account_key, 2)]));
+
+        // Sees the current state
+        transaction_context
+            .accounts()
+            .try_borrow_mut(1)
+            .unwrap()
+            .set_lamports(3);
+        assert_eq!(scan_token_balances(), Ok(vec![(token_account_key, 3)]));
+
+        let _account = transaction_context.accounts().try_borrow_mut(0).unwrap();
+        assert_eq!(
+            scan_token_balances(),
+            Err(InstructionError::AccountBorrowFailed)
+        );
+    }
+
+    #[test]
+    fn test_account_keys() {
+        let transaction_context = new_transaction_context(vec![]);
+        assert!(transaction_context.get_account_keys().is_empty());
+        assert_eq!(
+            transaction_context.get_fee_payer_key(),
+            Err(InstructionError::NotEnoughAccountKeys)
+        );
+
+        let fee_payer = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+        let transaction_context = new_transaction_context(vec![
+            (
+                fee_payer,
+                AccountSharedData::new(1, 0, &system_program::id()),
+            ),
+            (other, AccountSharedData::new(1, 0, &system_program::id())),
+        ]);
+        assert_eq!(transaction_context.get_account_keys(), &[fee_payer, other]);
+        assert_eq!(transaction_context.get_fee_payer_key(), Ok(&fee_payer));
+    }
+
+    #[test]
+    fn test_state_digest() {
+        let key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let new_transaction_accounts = || {
+            vec![
+                (key, AccountSharedData::new(1, 2, &owner)),
+                (
+                    Pubkey::new_unique(),
+                    AccountSharedData::new(1, 0, &system_program::id()),
+                ),
+            ]
+        };
+        let transaction_accounts = new_transaction_accounts();
+        let digest = new_transaction_context(transaction_accounts.clone())
+            .state_digest()
+            .unwrap();
+        assert_eq!(
+            new_transaction_context(transaction_accounts.clone()).state_digest(),
+            Ok(digest)
+        );
+        // Changes with the key of an untouched account
+        assert_ne!(
+            new_transaction_context(new_transaction_accounts()).state_digest(),
+            Ok(digest)
+        );
+
+        let mutations: [fn(&mut AccountSharedData); 5] = [
+            |account| account.set_lamports(2),
+            |account| account.set_owner(Pubkey::new_unique()),
+            |account| account.set_executable(true),
+            |account| account.set_data_from_slice(&[0, 0, 0]),
+            |account| account.data_as_mut_slice().copy_from_slice(&[0, 1]),
+        ];
+        for mutation in mutations {
+            let transaction_context = new_transaction_context(transaction_accounts.clone());
+            mutation(&mut transaction_context.accounts().try_borrow_mut(0).unwrap());
+            assert_ne!(transaction_context.state_digest(), Ok(digest));
+        }
+
+        let transaction_context = new_transaction_context(transaction_accounts);
+        let _account = transaction_context.accounts().try_borrow_mut(1).unwrap();
+        assert_eq!(
+            transaction_context.state_digest(),
+            Err(InstructionError::AccountBorrowFailed)
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let transaction_accounts = vec![
+            (key, AccountSharedData::new(1, 4, &owner)),
+            (
+                Pubkey::new_unique(),
+                AccountSharedData::new(1, 0, &system_program::id()),
+            ),
+        ];
+        let before = new_transaction_context(transaction_accounts.clone());
+        let after = new_transaction_context(transaction_accounts);
+        assert_eq!(before.diff(&after), Ok(vec![]));
+        assert_same_accounts(&before, &after);
+
+        let new_owner = Pubkey::new_unique();
+        {
+            let mut account = after.accounts().try_borrow_mut(0).unwrap();
+            account.set_lamports(2);
+            account.set_owner(new_owner);
+            account.set_data_from_slice(&[0, 0, 1, 0, 0]);
+        }
+        assert_eq!(
+            before.diff(&after),
+            Ok(vec![AccountDiff {
+                index_in_transaction: 0,
+                key,
+                fields: vec![
+                    AccountFieldDiff::Lamports {
+                        before: 1,
+                        after: 2
+                    },
+                    AccountFieldDiff::Owner {
+                        before: owner,
+                        after: new_owner,
+                    },
+                    AccountFieldDiff::Data {
+                        first_differing_offset: 2,
+                        before_len: 4,
+                        after_len: 5,
+                    },
+                ],
+            }])
+        );
+
+        // A pure truncation differs at the end of the shorter data
+        after
+            .accounts()
+            .try_borrow_mut(0)
+            .unwrap()
+            .set_data_from_slice(&[0, 0]);
+        assert_eq!(
+            before
+                .diff(&after)
+                .unwrap()
+                .pop()
+                .and_then(|account_diff| account_diff.fields.last().cloned()),
+            Some(AccountFieldDiff::Data {
+                first_differing_offset: 2,
+                before_len: 4,
+                after_len: 2,
+            })
+        );
+
+        assert_eq!(
+            before.diff(&new_transaction_context(vec![])),
+            Err(InstructionError::NotEnoughAccountKeys)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "accounts differ")]
+    fn test_assert_same_accounts_panics_on_difference() {
+        let transaction_accounts = vec![(
+            Pubkey::new_unique(),
+            AccountSharedData::new(1, 0, &system_program::id()),
+        )];
+        let before = new_transaction_context(transaction_accounts.clone());
+        let after = new_transaction_context(transaction_accounts);
+        after.accounts().try_borrow_mut(0).unwrap().set_lamports(2);
+        assert_same_accounts(&before, &after);
+    }
+
+    #[test]
+    fn test_sysvar_read_cache_debug_is_order_independent() {
+        let new_accounts = || {
+            new_transaction_context(vec![
+                (
+                    Clock::id(),
+                    create_account_shared_data_for_test(&Clock::default()),
+                ),
+                (
+                    Rent::id(),
+                    create_account_shared_data_for_test(&Rent::default()),
+                ),
+            ])
+        };
+        let forward = new_accounts();
+        forward.read_sysvar::<Clock>(0).unwrap();
+        forward.read_sysvar::<Rent>(1).unwrap();
+        let backward = new_accounts();
+        backward.read_sysvar::<Rent>(1).unwrap();
+        backward.read_sysvar::<Clock>(0).unwrap();
+        // Both orders print the indices sorted, so the output is deterministic
+        let expected = "SysvarReadCache { cached_indices: [0, 1] }";
+        assert_eq!(
+            format!("{:?}", forward.accounts().sysvar_read_cache),
+            expected
+        );
+        assert_eq!(
+            format!("{:?}", backward.accounts().sysvar_read_cache),
+            expected
+        );
+    }
+}